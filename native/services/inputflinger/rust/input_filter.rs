@@ -25,19 +25,96 @@ use com_android_server_inputflinger::aidl::com::android::server::inputflinger::{
     IInputThread::{IInputThread, IInputThreadCallback::IInputThreadCallback},
     InputFilterConfiguration::InputFilterConfiguration,
     KeyEvent::KeyEvent,
+    KeyEventAction::KeyEventAction,
+    MotionEvent::MotionEvent,
 };
 
 use crate::bounce_keys_filter::BounceKeysFilter;
 use crate::input_filter_thread::InputFilterThread;
+use crate::mouse_keys_filter::MouseKeysFilter;
 use crate::slow_keys_filter::SlowKeysFilter;
 use crate::sticky_keys_filter::StickyKeysFilter;
 use input::ModifierState;
 use log::{error, info};
-use std::sync::{Arc, Mutex, RwLock};
+use parking_lot::{Mutex, RwLock};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+
+/// Mirrors {@code android.view.KeyEvent#FLAG_CANCELED}, used to mark the synthetic UP events we
+/// generate to resync a key that would otherwise be left logically stuck.
+const FLAG_CANCELED: i32 = 0x20;
+
+/// Identifies a key that may currently be held down: the device it originated from and its key
+/// code.
+type HeldKeyId = (/* deviceId */ i32, /* keyCode */ i32);
+
+/// Maximum number of events buffered between the binder thread and the `InputFilterThread` that
+/// actually runs the filter chain. Bounded so a slow filter can only ever back up a fixed amount
+/// of memory rather than the whole native input pipeline.
+const EVENT_QUEUE_CAPACITY: usize = 128;
+
+/// An event awaiting processing on `InputFilterThread`.
+enum QueuedEvent {
+    Key(KeyEvent),
+    Motion(MotionEvent),
+}
+
+/// Builds the canceled UP counterpart of a held key's last known DOWN event, copying its
+/// metaState/source/etc. so downstream C++ sees a plausible, balanced event.
+fn canceled_key_event(down_event: &KeyEvent) -> KeyEvent {
+    KeyEvent { action: KeyEventAction::UP, flags: down_event.flags | FLAG_CANCELED, ..*down_event }
+}
+
+/// Restricts a sub-filter to a subset of input devices, computed once from an
+/// `InputFilterConfiguration` when the chain is (re)built. This lets the same `InputFilter`
+/// instance, say, debounce an external keyboard while leaving a virtual/on-screen keyboard
+/// untouched, analogous to evdev making filtering decisions against the specific source device
+/// rather than globally.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum DeviceScope {
+    /// Applies to every device.
+    All,
+    /// Applies only to devices reported as external (see [`DeviceInfo::external`]).
+    ExternalOnly,
+    /// Applies only to the explicitly listed device ids.
+    Devices(HashSet<i32>),
+}
+
+impl DeviceScope {
+    pub(crate) fn from_config(config: &InputFilterConfiguration) -> DeviceScope {
+        if !config.deviceIdAllowlist.is_empty() {
+            DeviceScope::Devices(config.deviceIdAllowlist.iter().copied().collect())
+        } else if config.externalDeviceScopeOnly {
+            DeviceScope::ExternalOnly
+        } else {
+            DeviceScope::All
+        }
+    }
+
+    /// Whether `device_id` is in scope, resolving "external" against the most recently reported
+    /// `DeviceInfo` list.
+    pub(crate) fn contains(&self, device_id: i32, known_devices: &[DeviceInfo]) -> bool {
+        match self {
+            DeviceScope::All => true,
+            DeviceScope::ExternalOnly => known_devices
+                .iter()
+                .any(|device_info| device_info.deviceId == device_id && device_info.external),
+            DeviceScope::Devices(device_ids) => device_ids.contains(&device_id),
+        }
+    }
+}
 
 /// Interface for all the sub input filters
 pub trait Filter {
     fn notify_key(&mut self, event: &KeyEvent);
+    /// Like evdev surfacing every event type through one stream rather than keys alone, this lets
+    /// a filter chain intercept and transform motion as well as key events. Most filters only
+    /// care about keys, so a default that does nothing is provided; any filter that wraps another
+    /// filter (the common pattern in this module) must still override this to forward to its
+    /// inner filter, or motion events will silently stop at that link in the chain.
+    fn notify_motion(&mut self, event: &MotionEvent) {
+        let _ = event;
+    }
     fn notify_devices_changed(&mut self, device_infos: &[DeviceInfo]);
     fn destroy(&mut self);
 }
@@ -45,17 +122,56 @@ pub trait Filter {
 struct InputFilterState {
     first_filter: Box<dyn Filter + Send + Sync>,
     enabled: bool,
+    // Keys that are currently down, keyed by (deviceId, keyCode). Used to resync state by
+    // synthesizing a canceled UP whenever the chain that saw the DOWN is torn down or its
+    // originating device disappears, so a key can never be left logically stuck. Sub-filters
+    // that buffer a DOWN without forwarding it yet (e.g. SlowKeys, before its threshold elapses)
+    // must drop a synthetic canceled UP for that key rather than releasing it further down the
+    // chain, since the DOWN was never actually emitted.
+    held_keys: HashMap<HeldKeyId, KeyEvent>,
+    // The most recently reported device list, replayed into a freshly built filter chain so a
+    // newly installed, device-scoped sub-filter (e.g. "external keyboard only") doesn't have to
+    // wait for the next notifyInputDevicesChanged to know which devices are in scope.
+    known_devices: Vec<DeviceInfo>,
+    // Events waiting to be run through the filter chain on `InputFilterThread`. notifyKey/
+    // notifyMotion only ever push here; nothing in this queue touches `first_filter` until it's
+    // popped on that thread, so the chain itself is only ever mutated from one place.
+    event_queue: VecDeque<QueuedEvent>,
+    // Keys needing a synthetic canceled UP once the queue catches up, mirroring evdev's
+    // SYN_DROPPED handling: either the DOWN's queue slot was reclaimed by an overflow before it
+    // ever reached the chain, or the DOWN already reached the chain (and is held) but the
+    // matching UP's slot was reclaimed instead. Either way a key can't be left logically pressed
+    // just because an event arrived during a burst. Keyed by (deviceId, keyCode), mapping to the
+    // original DOWN event whose fields seed the synthesized UP.
+    dropped_downs: HashMap<HeldKeyId, KeyEvent>,
+    // Largest `event_queue` length ever observed, reported to `IInputFilterCallbacks` so the
+    // native side has visibility into queueing pressure.
+    high_water_mark: usize,
 }
 
 /// The rust implementation of InputFilter
 pub struct InputFilter {
     // In order to have multiple immutable references to the callbacks that is thread safe need to
-    // wrap the callbacks in Arc<RwLock<...>>
+    // wrap the callbacks in Arc<RwLock<...>>. parking_lot's RwLock is used instead of std's so this
+    // lock is fair: a storm of notify_key readers (see BaseFilter::notify_key) cannot indefinitely
+    // delay a writer trying to land, e.g., a config change that needs to touch the callback.
     callbacks: Arc<RwLock<Strong<dyn IInputFilterCallbacks>>>,
-    // Access to mutable references to mutable state (includes access to filters, enabled, etc.) is
-    // guarded by Mutex for thread safety
-    state: Mutex<InputFilterState>,
+    // Access to mutable state (includes access to filters, enabled, etc.) is guarded by a
+    // parking_lot RwLock for thread safety, wrapped in an Arc so the closures run on
+    // InputFilterThread (the queue drain, a device list update, a chain rebuild) can share it
+    // without borrowing from `self`. Rebuilding the chain now happens on that thread too, not the
+    // calling binder thread, so isEnabled()/diagnostics only ever wait behind a writer (an
+    // InputFilterThread task, or notifyKey/notifyMotion briefly pushing onto the queue), never
+    // behind each other.
+    state: Arc<RwLock<InputFilterState>>,
     input_filter_thread: InputFilterThread,
+    // The set of (deviceId, keyCode) pairs `BaseFilter` has actually forwarded a DOWN for to
+    // native C++ and not yet released. Shared (rather than rebuilt) across chain rebuilds, since
+    // it's native's view of what's held, not any particular chain's: a sub-filter upstream of
+    // `BaseFilter` (e.g. MouseKeysFilter) can swallow a DOWN entirely, and if that filter is gone
+    // by the time the matching UP arrives, `BaseFilter` still needs to know never to forward it,
+    // or native ends up with an orphan UP for a key it never saw pressed.
+    native_held_keys: Arc<Mutex<HashSet<HeldKeyId>>>,
 }
 
 impl Interface for InputFilter {}
@@ -64,91 +180,333 @@ impl InputFilter {
     /// Create a new InputFilter instance.
     pub fn new(callbacks: Strong<dyn IInputFilterCallbacks>) -> InputFilter {
         let ref_callbacks = Arc::new(RwLock::new(callbacks));
-        let base_filter = Box::new(BaseFilter::new(ref_callbacks.clone()));
-        Self::create_input_filter(base_filter, ref_callbacks)
+        let native_held_keys = Arc::new(Mutex::new(HashSet::new()));
+        let base_filter = Box::new(BaseFilter::new(ref_callbacks.clone(), native_held_keys.clone()));
+        Self::create_input_filter_with_native_held_keys(base_filter, ref_callbacks, native_held_keys)
     }
 
     /// Create test instance of InputFilter
     fn create_input_filter(
         first_filter: Box<dyn Filter + Send + Sync>,
         callbacks: Arc<RwLock<Strong<dyn IInputFilterCallbacks>>>,
+    ) -> InputFilter {
+        Self::create_input_filter_with_native_held_keys(
+            first_filter,
+            callbacks,
+            Arc::new(Mutex::new(HashSet::new())),
+        )
+    }
+
+    fn create_input_filter_with_native_held_keys(
+        first_filter: Box<dyn Filter + Send + Sync>,
+        callbacks: Arc<RwLock<Strong<dyn IInputFilterCallbacks>>>,
+        native_held_keys: Arc<Mutex<HashSet<HeldKeyId>>>,
     ) -> InputFilter {
         Self {
             callbacks: callbacks.clone(),
-            state: Mutex::new(InputFilterState { first_filter, enabled: false }),
+            state: Arc::new(RwLock::new(InputFilterState {
+                first_filter,
+                enabled: false,
+                held_keys: HashMap::new(),
+                known_devices: Vec::new(),
+                event_queue: VecDeque::new(),
+                dropped_downs: HashMap::new(),
+                high_water_mark: 0,
+            })),
             input_filter_thread: InputFilterThread::new(InputFilterThreadCreator::new(callbacks)),
+            native_held_keys,
         }
     }
 }
 
 impl IInputFilter for InputFilter {
     fn isEnabled(&self) -> binder::Result<bool> {
-        Result::Ok(self.state.lock().unwrap().enabled)
+        // A plain read: never waits behind another reader.
+        Result::Ok(self.state.read().enabled)
     }
 
     fn notifyKey(&self, event: &KeyEvent) -> binder::Result<()> {
-        let first_filter = &mut self.state.lock().unwrap().first_filter;
-        first_filter.notify_key(event);
+        // Only enqueue here; the chain itself is only ever run on InputFilterThread so a slow
+        // filter (e.g. SlowKeys scheduling) can't back-pressure this binder thread.
+        self.enqueue(QueuedEvent::Key(*event));
+        Result::Ok(())
+    }
+
+    fn notifyMotion(&self, event: &MotionEvent) -> binder::Result<()> {
+        self.enqueue(QueuedEvent::Motion(*event));
         Result::Ok(())
     }
 
     fn notifyInputDevicesChanged(&self, device_infos: &[DeviceInfo]) -> binder::Result<()> {
-        let first_filter = &mut self.state.lock().unwrap().first_filter;
-        first_filter.notify_devices_changed(device_infos);
+        // Like notifyKey/notifyMotion, hand the actual work to InputFilterThread instead of
+        // running it here: this touches `first_filter` (via flush_queue) just like a config
+        // change does, so it must not run on the calling binder thread either.
+        let state = self.state.clone();
+        let device_infos = device_infos.to_vec();
+        self.input_filter_thread.schedule_after(0, move || {
+            InputFilter::apply_devices_changed(&mut state.write(), &device_infos);
+        });
         Result::Ok(())
     }
 
     fn notifyConfigurationChanged(&self, config: &InputFilterConfiguration) -> binder::Result<()> {
-        {
-            let mut state = self.state.lock().unwrap();
-            state.first_filter.destroy();
-            let mut first_filter: Box<dyn Filter + Send + Sync> =
-                Box::new(BaseFilter::new(self.callbacks.clone()));
-            if config.stickyKeysEnabled {
-                first_filter = Box::new(StickyKeysFilter::new(
-                    first_filter,
-                    ModifierStateListener::new(self.callbacks.clone()),
-                ));
-                state.enabled = true;
-                info!("Sticky keys filter is installed");
+        // Rebuilding the chain can run arbitrary sub-filter setup and replays queued events
+        // through it (flush_queue), so this is scheduled on InputFilterThread rather than run
+        // inline: the chain is only ever mutated from that one thread, never the calling binder
+        // thread.
+        let state = self.state.clone();
+        let callbacks = self.callbacks.clone();
+        let input_filter_thread = self.input_filter_thread.clone();
+        let native_held_keys = self.native_held_keys.clone();
+        let config = config.clone();
+        self.input_filter_thread.schedule_after(0, move || {
+            InputFilter::apply_configuration_changed(
+                &mut state.write(),
+                &config,
+                &callbacks,
+                &input_filter_thread,
+                &native_held_keys,
+            );
+        });
+        Result::Ok(())
+    }
+}
+
+impl InputFilter {
+    /// Buffers `event` for processing on `InputFilterThread`, evicting the oldest buffered event
+    /// if the queue is already full.
+    fn enqueue(&self, event: QueuedEvent) {
+        // Compute the new high-water-mark with `state` held, but report it (a binder call into
+        // native C++) only after the guard is dropped below: holding `state` across that call
+        // would block every other notifyKey/notifyMotion/isEnabled caller, and any queued
+        // InputFilterThread task, on a round-trip, right when the queue is already under pressure.
+        let new_high_water_mark = {
+            let mut state = self.state.write();
+            if state.event_queue.len() >= EVENT_QUEUE_CAPACITY {
+                // Mirrors evdev's SYN_DROPPED handling: rather than block the producer, drop the
+                // oldest buffered event. If it was a DOWN, remember it so the resync at the end of
+                // the next drain releases it instead of leaving it logically pressed.
+                if let Some(QueuedEvent::Key(dropped)) = state.event_queue.pop_front() {
+                    let key_id = (dropped.deviceId, dropped.keyCode);
+                    match dropped.action {
+                        KeyEventAction::DOWN => {
+                            state.dropped_downs.insert(key_id, dropped);
+                        }
+                        KeyEventAction::UP => {
+                            // The DOWN already reached the chain and is held; losing this UP
+                            // would otherwise leave the key logically stuck forever, since
+                            // nothing later will ever resync it. Reuse the DOWN resync path: the
+                            // canceled UP gets synthesized from the original DOWN's fields.
+                            if let Some(down_event) = state.held_keys.remove(&key_id) {
+                                state.dropped_downs.insert(key_id, down_event);
+                            }
+                        }
+                        _ => {}
+                    }
+                }
             }
-            if config.slowKeysThresholdNs > 0 {
-                first_filter = Box::new(SlowKeysFilter::new(
-                    first_filter,
-                    config.slowKeysThresholdNs,
-                    self.input_filter_thread.clone(),
-                ));
-                state.enabled = true;
-                info!("Slow keys filter is installed");
+            state.event_queue.push_back(event);
+            let queue_len = state.event_queue.len();
+            if queue_len > state.high_water_mark {
+                state.high_water_mark = queue_len;
+                Some(queue_len)
+            } else {
+                None
             }
-            if config.bounceKeysThresholdNs > 0 {
-                first_filter =
-                    Box::new(BounceKeysFilter::new(first_filter, config.bounceKeysThresholdNs));
-                state.enabled = true;
-                info!("Bounce keys filter is installed");
+        };
+        if let Some(queue_len) = new_high_water_mark {
+            match self.callbacks.read().reportEventQueueHighWaterMark(queue_len as i32) {
+                Ok(_) => (),
+                _ => error!("Failed to report event queue high-water mark"),
             }
-            state.first_filter = first_filter;
         }
-        Result::Ok(())
+        self.schedule_drain();
+    }
+
+    /// Asks `InputFilterThread` to drain the queue. Safe to call more than once for the same
+    /// burst of events; a drain that finds nothing queued is a no-op.
+    fn schedule_drain(&self) {
+        let state = self.state.clone();
+        self.input_filter_thread.schedule_after(0, move || {
+            InputFilter::flush_queue(&mut state.write());
+        });
+    }
+
+    /// Runs every currently queued event through `state.first_filter`, then resyncs any key a
+    /// queue overflow discarded before it could reach a stable state: either its DOWN never made
+    /// it to the chain, or its DOWN did and the matching UP was discarded instead. Must only be
+    /// called with `state` already locked, from InputFilterThread; like `apply_devices_changed`
+    /// and `apply_configuration_changed`, which also drive the chain, it never runs on a binder
+    /// thread.
+    fn flush_queue(state: &mut InputFilterState) {
+        while let Some(event) = state.event_queue.pop_front() {
+            match event {
+                QueuedEvent::Key(key_event) => {
+                    match key_event.action {
+                        KeyEventAction::DOWN => {
+                            state.held_keys.insert((key_event.deviceId, key_event.keyCode), key_event);
+                        }
+                        KeyEventAction::UP => {
+                            state.held_keys.remove(&(key_event.deviceId, key_event.keyCode));
+                        }
+                        _ => {}
+                    }
+                    // This key made it through after all; it no longer needs an overflow resync.
+                    state.dropped_downs.remove(&(key_event.deviceId, key_event.keyCode));
+                    state.first_filter.notify_key(&key_event);
+                }
+                QueuedEvent::Motion(motion_event) => {
+                    state.first_filter.notify_motion(&motion_event);
+                }
+            }
+        }
+        if state.dropped_downs.is_empty() {
+            return;
+        }
+        let released_events: Vec<KeyEvent> =
+            state.dropped_downs.values().map(canceled_key_event).collect();
+        state.dropped_downs.clear();
+        for event in &released_events {
+            state.first_filter.notify_key(event);
+        }
+    }
+
+    /// Applies a `notifyInputDevicesChanged` update to an already-locked `state`. Runs on
+    /// `InputFilterThread`; see `notifyInputDevicesChanged`.
+    fn apply_devices_changed(state: &mut InputFilterState, device_infos: &[DeviceInfo]) {
+        // Flush whatever's still queued through the outgoing chain first, so `held_keys` (and
+        // whatever the chain has observed) reflects every key that arrived before this call.
+        InputFilter::flush_queue(state);
+        let current_device_ids: HashSet<i32> =
+            device_infos.iter().map(|device_info| device_info.deviceId).collect();
+        // Any held key whose device is no longer present would otherwise stay stuck forever,
+        // since its UP can never arrive from a device that's gone.
+        let released_events: Vec<KeyEvent> = state
+            .held_keys
+            .values()
+            .filter(|down_event| !current_device_ids.contains(&down_event.deviceId))
+            .map(canceled_key_event)
+            .collect();
+        state.held_keys.retain(|_, down_event| current_device_ids.contains(&down_event.deviceId));
+        for event in &released_events {
+            state.first_filter.notify_key(event);
+        }
+        state.known_devices = device_infos.to_vec();
+        state.first_filter.notify_devices_changed(device_infos);
+    }
+
+    /// Applies a `notifyConfigurationChanged` update to an already-locked `state`, rebuilding the
+    /// filter chain. Runs on `InputFilterThread`; see `notifyConfigurationChanged`.
+    fn apply_configuration_changed(
+        state: &mut InputFilterState,
+        config: &InputFilterConfiguration,
+        callbacks: &Arc<RwLock<Strong<dyn IInputFilterCallbacks>>>,
+        input_filter_thread: &InputFilterThread,
+        native_held_keys: &Arc<Mutex<HashSet<HeldKeyId>>>,
+    ) {
+        // Run anything still queued through the outgoing chain before tearing it down, so the
+        // new chain doesn't inherit events meant for the old one.
+        InputFilter::flush_queue(state);
+        // The chain about to be destroyed may still have keys held down that it never saw
+        // released; resync by releasing them through the outgoing chain before tearing it down,
+        // so downstream C++ never observes an unbalanced DOWN.
+        let released_events: Vec<KeyEvent> =
+            state.held_keys.values().map(canceled_key_event).collect();
+        state.held_keys.clear();
+        for event in &released_events {
+            state.first_filter.notify_key(event);
+        }
+        state.first_filter.destroy();
+        let device_scope = DeviceScope::from_config(config);
+        let mut first_filter: Box<dyn Filter + Send + Sync> =
+            Box::new(BaseFilter::new(callbacks.clone(), native_held_keys.clone()));
+        if config.stickyKeysEnabled {
+            first_filter = Box::new(StickyKeysFilter::new(
+                first_filter,
+                ModifierStateListener::new(callbacks.clone()),
+                device_scope.clone(),
+            ));
+            state.enabled = true;
+            info!("Sticky keys filter is installed");
+        }
+        if config.slowKeysThresholdNs > 0 {
+            first_filter = Box::new(SlowKeysFilter::new(
+                first_filter,
+                config.slowKeysThresholdNs,
+                input_filter_thread.clone(),
+                device_scope.clone(),
+            ));
+            state.enabled = true;
+            info!("Slow keys filter is installed");
+        }
+        if config.bounceKeysThresholdNs > 0 {
+            first_filter = Box::new(BounceKeysFilter::new(
+                first_filter,
+                config.bounceKeysThresholdNs,
+                device_scope.clone(),
+            ));
+            state.enabled = true;
+            info!("Bounce keys filter is installed");
+        }
+        if config.mouseKeysEnabled {
+            first_filter = Box::new(MouseKeysFilter::new(
+                first_filter,
+                input_filter_thread.clone(),
+                device_scope.clone(),
+            ));
+            state.enabled = true;
+            info!("Mouse keys filter is installed");
+        }
+        // Replay the last known device list so a freshly installed, device-scoped sub-filter
+        // knows which devices are in scope without waiting for the next device change.
+        first_filter.notify_devices_changed(&state.known_devices);
+        state.first_filter = first_filter;
     }
 }
 
 struct BaseFilter {
     callbacks: Arc<RwLock<Strong<dyn IInputFilterCallbacks>>>,
+    native_held_keys: Arc<Mutex<HashSet<HeldKeyId>>>,
 }
 
 impl BaseFilter {
-    fn new(callbacks: Arc<RwLock<Strong<dyn IInputFilterCallbacks>>>) -> BaseFilter {
-        Self { callbacks }
+    fn new(
+        callbacks: Arc<RwLock<Strong<dyn IInputFilterCallbacks>>>,
+        native_held_keys: Arc<Mutex<HashSet<HeldKeyId>>>,
+    ) -> BaseFilter {
+        Self { callbacks, native_held_keys }
     }
 }
 
 impl Filter for BaseFilter {
     fn notify_key(&mut self, event: &KeyEvent) {
-        match self.callbacks.read().unwrap().sendKeyEvent(event) {
+        let key_id = (event.deviceId, event.keyCode);
+        if event.action == KeyEventAction::UP && !self.native_held_keys.lock().contains(&key_id) {
+            // Native never saw a DOWN for this key (e.g. a sub-filter upstream, like
+            // MouseKeysFilter, consumed it entirely), so forwarding this UP would leave native
+            // with an unbalanced release instead of the unbalanced press it never had.
+            return;
+        }
+        match self.callbacks.read().sendKeyEvent(event) {
             Ok(_) => (),
             _ => error!("Failed to send key event back to native C++"),
         }
+        match event.action {
+            KeyEventAction::DOWN => {
+                self.native_held_keys.lock().insert(key_id);
+            }
+            KeyEventAction::UP => {
+                self.native_held_keys.lock().remove(&key_id);
+            }
+            _ => {}
+        }
+    }
+
+    fn notify_motion(&mut self, event: &MotionEvent) {
+        match self.callbacks.read().sendMotionEvent(event) {
+            Ok(_) => (),
+            _ => error!("Failed to send motion event back to native C++"),
+        }
     }
 
     fn notify_devices_changed(&mut self, _device_infos: &[DeviceInfo]) {
@@ -175,7 +533,7 @@ impl ModifierStateListener {
         modifier_state: ModifierState,
         locked_modifier_state: ModifierState,
     ) {
-        let _ = self.0.read().unwrap().onModifierStateChanged(
+        let _ = self.0.read().onModifierStateChanged(
             modifier_state.bits() as i32,
             locked_modifier_state.bits() as i32,
         );
@@ -198,23 +556,66 @@ impl InputFilterThreadCreator {
         &self,
         input_thread_callback: &Strong<dyn IInputThreadCallback>,
     ) -> Strong<dyn IInputThread> {
-        self.0.read().unwrap().createInputFilterThread(input_thread_callback).unwrap()
+        self.0.read().createInputFilterThread(input_thread_callback).unwrap()
     }
 }
 
+/// Periodically asks parking_lot to check the locks taken out across this module (and any other
+/// parking_lot users linked into the same process) for deadlock cycles, logging a backtrace for
+/// each thread involved if one is found. Gated behind a build feature since the scan adds a small
+/// amount of background overhead that most builds won't want; enable it for local debugging or
+/// dogfood builds suspected of a lock-ordering bug. The caller (native service init) is expected to
+/// spawn this once at process startup, mirroring how it already hands InputFilter its callbacks.
+#[cfg(feature = "deadlock_detection")]
+pub fn start_deadlock_detector() {
+    std::thread::Builder::new()
+        .name("inputfilter_deadlock_detector".to_string())
+        .spawn(|| loop {
+            std::thread::sleep(std::time::Duration::from_secs(10));
+            for deadlock in parking_lot::deadlock::check_deadlock() {
+                for thread in deadlock {
+                    error!(
+                        "Potential deadlock detected on thread {:?}:\n{:?}",
+                        thread.thread_id(),
+                        thread.backtrace()
+                    );
+                }
+            }
+        })
+        .unwrap();
+}
+
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use crate::input_filter::{
-        test_callbacks::TestCallbacks, test_filter::TestFilter, InputFilter,
+        test_callbacks::TestCallbacks, test_filter::TestFilter, DeviceScope, InputFilter,
     };
     use android_hardware_input_common::aidl::android::hardware::input::common::Source::Source;
     use binder::Strong;
     use com_android_server_inputflinger::aidl::com::android::server::inputflinger::{
         DeviceInfo::DeviceInfo, IInputFilter::IInputFilter,
         InputFilterConfiguration::InputFilterConfiguration, KeyEvent::KeyEvent,
-        KeyEventAction::KeyEventAction,
+        KeyEventAction::KeyEventAction, MotionEvent::MotionEvent,
+        MotionEventAction::MotionEventAction,
     };
+    use std::collections::HashSet;
     use std::sync::{Arc, RwLock};
+    use std::time::{Duration, Instant};
+
+    /// Polls `condition` until it's true or a generous timeout elapses, for assertions that must
+    /// observe work done asynchronously on `InputFilterThread` rather than synchronously on the
+    /// calling (binder) thread. Also used by other filters' tests (e.g. `mouse_keys_filter`) that
+    /// need to wait on the same kind of background scheduling.
+    pub(crate) fn wait_for(timeout: Duration, mut condition: impl FnMut() -> bool) -> bool {
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            if condition() {
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        condition()
+    }
 
     #[test]
     fn test_not_enabled_with_default_filter() {
@@ -231,6 +632,8 @@ mod tests {
         let input_filter = InputFilter::new(Strong::new(Box::new(test_callbacks.clone())));
         let event = create_key_event();
         assert!(input_filter.notifyKey(&event).is_ok());
+        // notifyKey only enqueues; the chain runs on InputFilterThread once it's drained there.
+        assert!(wait_for(Duration::from_secs(1), || test_callbacks.last_event().is_some()));
         assert_eq!(test_callbacks.last_event().unwrap(), event);
     }
 
@@ -244,9 +647,34 @@ mod tests {
         );
         let event = create_key_event();
         assert!(input_filter.notifyKey(&event).is_ok());
+        assert!(wait_for(Duration::from_secs(1), || test_filter.last_event().is_some()));
         assert_eq!(test_filter.last_event().unwrap(), event);
     }
 
+    #[test]
+    fn test_notify_motion_with_no_filters() {
+        let test_callbacks = TestCallbacks::new();
+        let input_filter = InputFilter::new(Strong::new(Box::new(test_callbacks.clone())));
+        let event = create_motion_event();
+        assert!(input_filter.notifyMotion(&event).is_ok());
+        assert!(wait_for(Duration::from_secs(1), || test_callbacks.last_motion_event().is_some()));
+        assert_eq!(test_callbacks.last_motion_event().unwrap(), event);
+    }
+
+    #[test]
+    fn test_notify_motion_with_filter() {
+        let test_filter = TestFilter::new();
+        let test_callbacks = TestCallbacks::new();
+        let input_filter = InputFilter::create_input_filter(
+            Box::new(test_filter.clone()),
+            Arc::new(RwLock::new(Strong::new(Box::new(test_callbacks)))),
+        );
+        let event = create_motion_event();
+        assert!(input_filter.notifyMotion(&event).is_ok());
+        assert!(wait_for(Duration::from_secs(1), || test_filter.last_motion_event().is_some()));
+        assert_eq!(test_filter.last_motion_event().unwrap(), event);
+    }
+
     #[test]
     fn test_notify_devices_changed() {
         let test_filter = TestFilter::new();
@@ -258,7 +686,8 @@ mod tests {
         assert!(input_filter
             .notifyInputDevicesChanged(&[DeviceInfo { deviceId: 0, external: true }])
             .is_ok());
-        assert!(test_filter.is_device_changed_called());
+        // notifyInputDevicesChanged only schedules the update; it runs on InputFilterThread.
+        assert!(wait_for(Duration::from_secs(1), || test_filter.is_device_changed_called()));
     }
 
     #[test]
@@ -270,9 +699,8 @@ mod tests {
             ..Default::default()
         });
         assert!(result.is_ok());
-        let result = input_filter.isEnabled();
-        assert!(result.is_ok());
-        assert!(result.unwrap());
+        // notifyConfigurationChanged only schedules the rebuild; it runs on InputFilterThread.
+        assert!(wait_for(Duration::from_secs(1), || input_filter.isEnabled().unwrap()));
     }
 
     #[test]
@@ -284,9 +712,7 @@ mod tests {
             ..Default::default()
         });
         assert!(result.is_ok());
-        let result = input_filter.isEnabled();
-        assert!(result.is_ok());
-        assert!(result.unwrap());
+        assert!(wait_for(Duration::from_secs(1), || input_filter.isEnabled().unwrap()));
     }
 
     #[test]
@@ -298,9 +724,7 @@ mod tests {
             ..Default::default()
         });
         assert!(result.is_ok());
-        let result = input_filter.isEnabled();
-        assert!(result.is_ok());
-        assert!(result.unwrap());
+        assert!(wait_for(Duration::from_secs(1), || input_filter.isEnabled().unwrap()));
     }
 
     #[test]
@@ -313,7 +737,221 @@ mod tests {
         );
         let _ = input_filter
             .notifyConfigurationChanged(&InputFilterConfiguration { ..Default::default() });
-        assert!(test_filter.is_destroy_called());
+        assert!(wait_for(Duration::from_secs(1), || test_filter.is_destroy_called()));
+    }
+
+    #[test]
+    fn test_swallowed_down_suppresses_orphan_up_after_reconfiguration() {
+        let test_callbacks = TestCallbacks::new();
+        let input_filter = InputFilter::new(Strong::new(Box::new(test_callbacks.clone())));
+
+        let _ = input_filter.notifyConfigurationChanged(&InputFilterConfiguration {
+            mouseKeysEnabled: true,
+            ..Default::default()
+        });
+        assert!(wait_for(Duration::from_secs(1), || input_filter.isEnabled().unwrap()));
+
+        // MouseKeysFilter consumes this directional numpad key entirely; native never sees a DOWN.
+        let down_event = KeyEvent { keyCode: 152 /* KEYCODE_NUMPAD_8 */, ..create_key_event() };
+        assert!(input_filter.notifyKey(&down_event).is_ok());
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(test_callbacks.last_event().is_none());
+
+        // Disabling mouse keys rebuilds the chain down to a lone BaseFilter.
+        let _ = input_filter
+            .notifyConfigurationChanged(&InputFilterConfiguration { ..Default::default() });
+        std::thread::sleep(Duration::from_millis(50));
+
+        // The real hardware UP now arrives through the MouseKeys-less chain; BaseFilter must
+        // still suppress it, since native never saw the matching DOWN.
+        let up_event = KeyEvent { action: KeyEventAction::UP, ..down_event };
+        assert!(input_filter.notifyKey(&up_event).is_ok());
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(test_callbacks.last_event().is_none());
+    }
+
+    #[test]
+    fn test_notify_configuration_changed_releases_held_keys() {
+        let test_filter = TestFilter::new();
+        let test_callbacks = TestCallbacks::new();
+        let input_filter = InputFilter::create_input_filter(
+            Box::new(test_filter.clone()),
+            Arc::new(RwLock::new(Strong::new(Box::new(test_callbacks)))),
+        );
+        let down_event = create_key_event();
+        assert!(input_filter.notifyKey(&down_event).is_ok());
+
+        let _ = input_filter
+            .notifyConfigurationChanged(&InputFilterConfiguration { ..Default::default() });
+
+        assert!(wait_for(Duration::from_secs(1), || test_filter
+            .last_event()
+            .is_some_and(|event| event.action == KeyEventAction::UP)));
+        let released_event = test_filter.last_event().unwrap();
+        assert_eq!(released_event.action, KeyEventAction::UP);
+        assert_eq!(released_event.deviceId, down_event.deviceId);
+        assert_eq!(released_event.keyCode, down_event.keyCode);
+        assert_ne!(released_event.flags & 0x20, 0);
+    }
+
+    #[test]
+    fn test_notify_input_devices_changed_releases_held_keys_for_removed_device() {
+        let test_filter = TestFilter::new();
+        let test_callbacks = TestCallbacks::new();
+        let input_filter = InputFilter::create_input_filter(
+            Box::new(test_filter.clone()),
+            Arc::new(RwLock::new(Strong::new(Box::new(test_callbacks)))),
+        );
+        let down_event = create_key_event();
+        assert!(input_filter.notifyKey(&down_event).is_ok());
+
+        // The device that produced the held key is no longer present.
+        assert!(input_filter.notifyInputDevicesChanged(&[]).is_ok());
+
+        assert!(wait_for(Duration::from_secs(1), || test_filter
+            .last_event()
+            .is_some_and(|event| event.action == KeyEventAction::UP)));
+        let released_event = test_filter.last_event().unwrap();
+        assert_eq!(released_event.action, KeyEventAction::UP);
+        assert_eq!(released_event.deviceId, down_event.deviceId);
+        assert_ne!(released_event.flags & 0x20, 0);
+    }
+
+    #[test]
+    fn test_notify_input_devices_changed_keeps_held_keys_for_present_device() {
+        let test_filter = TestFilter::new();
+        let test_callbacks = TestCallbacks::new();
+        let input_filter = InputFilter::create_input_filter(
+            Box::new(test_filter.clone()),
+            Arc::new(RwLock::new(Strong::new(Box::new(test_callbacks)))),
+        );
+        let down_event = create_key_event();
+        assert!(input_filter.notifyKey(&down_event).is_ok());
+        assert!(wait_for(Duration::from_secs(1), || test_filter.last_event().is_some()));
+
+        assert!(input_filter
+            .notifyInputDevicesChanged(&[DeviceInfo { deviceId: down_event.deviceId, external: true }])
+            .is_ok());
+        assert!(wait_for(Duration::from_secs(1), || test_filter.is_device_changed_called()));
+
+        // The held key's device is still present, so no synthetic release should have been sent;
+        // the DOWN should remain the last event seen by the chain.
+        assert_eq!(test_filter.last_event().unwrap(), down_event);
+    }
+
+    #[test]
+    fn test_notify_key_overflow_releases_discarded_down() {
+        let test_filter = TestFilter::new();
+        let test_callbacks = TestCallbacks::new();
+        let input_filter = InputFilter::create_input_filter(
+            Box::new(test_filter.clone()),
+            Arc::new(RwLock::new(Strong::new(Box::new(test_callbacks)))),
+        );
+        // The first DOWN should be evicted once the queue fills past capacity; once everything
+        // drains, it must be released with a synthetic UP rather than left held.
+        let first_down = KeyEvent { keyCode: 1, ..create_key_event() };
+        assert!(input_filter.notifyKey(&first_down).is_ok());
+        for key_code in 2..=129 {
+            let event = KeyEvent { keyCode: key_code, ..create_key_event() };
+            assert!(input_filter.notifyKey(&event).is_ok());
+        }
+
+        assert!(wait_for(Duration::from_secs(1), || {
+            test_filter
+                .last_event()
+                .is_some_and(|event| event.action == KeyEventAction::UP
+                    && event.keyCode == first_down.keyCode)
+        }));
+        let released_event = test_filter.last_event().unwrap();
+        assert_eq!(released_event.deviceId, first_down.deviceId);
+        assert_ne!(released_event.flags & 0x20, 0);
+    }
+
+    #[test]
+    fn test_notify_key_overflow_releases_discarded_up() {
+        let test_filter = TestFilter::new();
+        let test_callbacks = TestCallbacks::new();
+        let input_filter = InputFilter::create_input_filter(
+            Box::new(test_filter.clone()),
+            Arc::new(RwLock::new(Strong::new(Box::new(test_callbacks)))),
+        );
+        // Let the DOWN reach the chain and drain first, so it's the matching UP (not the DOWN)
+        // that ends up discarded by the overflow below.
+        let held_down = KeyEvent { keyCode: 1, ..create_key_event() };
+        assert!(input_filter.notifyKey(&held_down).is_ok());
+        assert!(wait_for(Duration::from_secs(1), || test_filter
+            .last_event()
+            .is_some_and(|event| event.action == KeyEventAction::DOWN)));
+
+        // The real UP should be evicted once the queue fills past capacity; once everything
+        // drains, the key must still be released with a synthetic UP rather than left held
+        // forever, since nothing else will ever resync it.
+        let held_up = KeyEvent { action: KeyEventAction::UP, ..held_down };
+        assert!(input_filter.notifyKey(&held_up).is_ok());
+        for key_code in 2..=129 {
+            let event = KeyEvent { keyCode: key_code, ..create_key_event() };
+            assert!(input_filter.notifyKey(&event).is_ok());
+        }
+
+        assert!(wait_for(Duration::from_secs(1), || {
+            test_filter
+                .last_event()
+                .is_some_and(|event| event.action == KeyEventAction::UP
+                    && event.keyCode == held_down.keyCode)
+        }));
+        let released_event = test_filter.last_event().unwrap();
+        assert_eq!(released_event.deviceId, held_down.deviceId);
+        assert_ne!(released_event.flags & 0x20, 0);
+    }
+
+    #[test]
+    fn test_notify_key_overflow_reports_high_water_mark() {
+        let test_callbacks = TestCallbacks::new();
+        let input_filter = InputFilter::new(Strong::new(Box::new(test_callbacks.clone())));
+        for key_code in 1..=130 {
+            let event = KeyEvent { keyCode: key_code, ..create_key_event() };
+            assert!(input_filter.notifyKey(&event).is_ok());
+        }
+
+        assert!(wait_for(Duration::from_secs(1), || test_callbacks.high_water_mark().is_some()));
+        assert_eq!(test_callbacks.high_water_mark().unwrap(), 128);
+    }
+
+    #[test]
+    fn test_device_scope_all_contains_any_device() {
+        assert!(DeviceScope::All.contains(1, &[]));
+    }
+
+    #[test]
+    fn test_device_scope_external_only_contains_external_device() {
+        let known_devices =
+            [DeviceInfo { deviceId: 1, external: true }, DeviceInfo { deviceId: 2, external: false }];
+        assert!(DeviceScope::ExternalOnly.contains(1, &known_devices));
+        assert!(!DeviceScope::ExternalOnly.contains(2, &known_devices));
+        assert!(!DeviceScope::ExternalOnly.contains(3, &known_devices));
+    }
+
+    #[test]
+    fn test_device_scope_devices_contains_only_listed_ids() {
+        let device_scope = DeviceScope::Devices(HashSet::from([1, 2]));
+        assert!(device_scope.contains(1, &[]));
+        assert!(!device_scope.contains(3, &[]));
+    }
+
+    #[test]
+    fn test_device_scope_from_config_defaults_to_all() {
+        let config = InputFilterConfiguration { ..Default::default() };
+        assert_eq!(DeviceScope::from_config(&config), DeviceScope::All);
+    }
+
+    #[test]
+    fn test_device_scope_from_config_prefers_allowlist_over_external_only() {
+        let config = InputFilterConfiguration {
+            externalDeviceScopeOnly: true,
+            deviceIdAllowlist: vec![5],
+            ..Default::default()
+        };
+        assert_eq!(DeviceScope::from_config(&config), DeviceScope::Devices(HashSet::from([5])));
     }
 
     fn create_key_event() -> KeyEvent {
@@ -333,13 +971,29 @@ mod tests {
             metaState: 0,
         }
     }
+
+    fn create_motion_event() -> MotionEvent {
+        MotionEvent {
+            id: 1,
+            deviceId: 1,
+            downTime: 0,
+            readTime: 0,
+            eventTime: 0,
+            source: Source::MOUSE,
+            displayId: 0,
+            policyFlags: 0,
+            action: MotionEventAction::MOVE,
+            relativeX: 0.0,
+            relativeY: 0.0,
+        }
+    }
 }
 
 #[cfg(test)]
 pub mod test_filter {
     use crate::input_filter::Filter;
     use com_android_server_inputflinger::aidl::com::android::server::inputflinger::{
-        DeviceInfo::DeviceInfo, KeyEvent::KeyEvent,
+        DeviceInfo::DeviceInfo, KeyEvent::KeyEvent, MotionEvent::MotionEvent,
     };
     use std::sync::{Arc, RwLock, RwLockWriteGuard};
 
@@ -347,6 +1001,7 @@ pub mod test_filter {
     struct TestFilterInner {
         is_device_changed_called: bool,
         last_event: Option<KeyEvent>,
+        last_motion_event: Option<MotionEvent>,
         is_destroy_called: bool,
     }
 
@@ -366,10 +1021,18 @@ pub mod test_filter {
             self.0.read().unwrap().last_event
         }
 
+        pub fn last_motion_event(&self) -> Option<MotionEvent> {
+            self.0.read().unwrap().last_motion_event
+        }
+
         pub fn clear(&mut self) {
             self.inner().last_event = None
         }
 
+        pub fn clear_motion(&mut self) {
+            self.inner().last_motion_event = None
+        }
+
         pub fn is_device_changed_called(&self) -> bool {
             self.0.read().unwrap().is_device_changed_called
         }
@@ -383,6 +1046,9 @@ pub mod test_filter {
         fn notify_key(&mut self, event: &KeyEvent) {
             self.inner().last_event = Some(*event);
         }
+        fn notify_motion(&mut self, event: &MotionEvent) {
+            self.inner().last_motion_event = Some(*event);
+        }
         fn notify_devices_changed(&mut self, _device_infos: &[DeviceInfo]) {
             self.inner().is_device_changed_called = true;
         }
@@ -399,6 +1065,7 @@ pub mod test_callbacks {
         IInputFilter::IInputFilterCallbacks::IInputFilterCallbacks,
         IInputThread::{BnInputThread, IInputThread, IInputThreadCallback::IInputThreadCallback},
         KeyEvent::KeyEvent,
+        MotionEvent::MotionEvent,
     };
     use input::ModifierState;
     use nix::{sys::time::TimeValLike, time::clock_gettime, time::ClockId};
@@ -410,7 +1077,9 @@ pub mod test_callbacks {
         last_modifier_state: ModifierState,
         last_locked_modifier_state: ModifierState,
         last_event: Option<KeyEvent>,
+        last_motion_event: Option<MotionEvent>,
         test_thread: Option<FakeCppThread>,
+        high_water_mark: Option<i32>,
     }
 
     #[derive(Default, Clone)]
@@ -431,8 +1100,13 @@ pub mod test_callbacks {
             self.0.read().unwrap().last_event
         }
 
+        pub fn last_motion_event(&self) -> Option<MotionEvent> {
+            self.0.read().unwrap().last_motion_event
+        }
+
         pub fn clear(&mut self) {
             self.inner().last_event = None;
+            self.inner().last_motion_event = None;
             self.inner().last_modifier_state = ModifierState::None;
             self.inner().last_locked_modifier_state = ModifierState::None;
         }
@@ -451,6 +1125,10 @@ pub mod test_callbacks {
             }
             false
         }
+
+        pub fn high_water_mark(&self) -> Option<i32> {
+            self.0.read().unwrap().high_water_mark
+        }
     }
 
     impl IInputFilterCallbacks for TestCallbacks {
@@ -459,6 +1137,11 @@ pub mod test_callbacks {
             Result::Ok(())
         }
 
+        fn sendMotionEvent(&self, event: &MotionEvent) -> binder::Result<()> {
+            self.inner().last_motion_event = Some(*event);
+            Result::Ok(())
+        }
+
         fn onModifierStateChanged(
             &self,
             modifier_state: i32,
@@ -471,6 +1154,11 @@ pub mod test_callbacks {
             Result::Ok(())
         }
 
+        fn reportEventQueueHighWaterMark(&self, size: i32) -> binder::Result<()> {
+            self.inner().high_water_mark = Some(size);
+            Result::Ok(())
+        }
+
         fn createInputFilterThread(
             &self,
             callback: &Strong<dyn IInputThreadCallback>,